@@ -1,9 +1,10 @@
 use amethyst::{
     assets::{AssetStorage, Handle, Loader},
-    core::{timing::Time, transform::TransformBundle, Transform},
+    audio::{output::Output, AudioBundle, AudioFormat, Source},
+    core::{math::Vector3, timing::Time, transform::TransformBundle, Transform},
     ecs::prelude::*,
     ecs::System,
-    input::{InputBundle, InputHandler, StringBindings, VirtualKeyCode},
+    input::{is_key_down, InputBundle, InputEvent, InputHandler, StringBindings, VirtualKeyCode},
     prelude::*,
     renderer::{
         camera::Camera,
@@ -13,8 +14,11 @@ use amethyst::{
         types::DefaultBackend,
         RenderingBundle, Texture,
     },
+    ui::{Anchor, FontAsset, TtfFormat, UiBundle, UiText, UiTransform},
     utils::application_root_dir,
 };
+use image::GenericImageView;
+use rand::Rng;
 
 /// パラメータ
 const SCREEN_WIDTH: f32 = 500.;
@@ -23,10 +27,89 @@ const OBSTACLE_WIDTH: f32 = 303.;
 const OBSTACLE_HEIGHT: f32 = 302.;
 const ROCK_HEIGHT: f32 = 52.;
 const GRAVITY: f32 = -0.5;
+const OBSTACLE_GAP_HEIGHT: f32 = 150.;
+const OBSTACLE_SPAWN_INTERVAL: f32 = 1.8;
+const OBSTACLE_POOL_SIZE: usize = 4;
+const ROCK_FLAP_FRAME_COUNT: usize = 3;
+const ROCK_FLAP_FRAME_DURATION: f32 = 0.12;
+const ROCK_TILT_FACTOR: f32 = 0.05;
+const ROCK_TILT_MAX: f32 = 0.6;
+const OBSTACLE_SPRITE_INDEX: usize = 3;
+const LEVEL_COLUMN_STRIDE: u32 = 20; // レベルPNGを何pxおきに1本のObstacleとしてサンプリングするか
+const OBSTACLE_SPACING: f32 = 250.; // レベルから生成するObstacle同士の間隔
+
+/// 2つの矩形(中心座標+サイズ)が重なっているかを判定する、標準的なAABB判定
+fn aabb_overlap(a_cx: f32, a_cy: f32, a_w: f32, a_h: f32, b_cx: f32, b_cy: f32, b_w: f32, b_h: f32) -> bool {
+    (a_cx - b_cx).abs() * 2. < (a_w + b_w) && (a_cy - b_cy).abs() * 2. < (a_h + b_h)
+}
+
+/// ObstacleのY座標・隙間情報から、上下2つの実体ブロックの(中心Y, 高さ)を計算する。
+/// 高さが0以下になったブロックは存在しない(隙間がそちら側の画面外まで続いている)ことを示す。
+/// 当たり判定(`rock_hits_obstacle`)と見た目の柱Sprite配置(`pillar_transform`)の両方で使う
+fn obstacle_blocks(obstacle_y: f32, gap_center_y: f32, gap_height: f32) -> ((f32, f32), (f32, f32)) {
+    let gap_top = gap_center_y + gap_height / 2.;
+    let gap_bottom = gap_center_y - gap_height / 2.;
+
+    // 上側のブロック: obstacle上端からgap_topまで
+    let top_block_top = obstacle_y + OBSTACLE_HEIGHT / 2.;
+    let top_block_height = (top_block_top - gap_top).max(0.);
+    let top_block_cy = gap_top + top_block_height / 2.;
+
+    // 下側のブロック: gap_bottomからobstacle下端まで
+    let bottom_block_bottom = obstacle_y - OBSTACLE_HEIGHT / 2.;
+    let bottom_block_height = (gap_bottom - bottom_block_bottom).max(0.);
+    let bottom_block_cy = bottom_block_bottom + bottom_block_height / 2.;
+
+    ((top_block_cy, top_block_height), (bottom_block_cy, bottom_block_height))
+}
+
+/// 岩がObstacle(隙間を挟んだ上下のブロック)と衝突したかどうかを判定
+/// 隙間([gap_center_y - gap_height/2, gap_center_y + gap_height/2])の中に収まっていれば
+/// すり抜け扱いにする
+fn rock_hits_obstacle(rock_y: f32, obstacle_x: f32, obstacle_y: f32, gap_center_y: f32, gap_height: f32) -> bool {
+    let rock_cx = SCREEN_WIDTH / 4.;
+    let ((top_cy, top_height), (bottom_cy, bottom_height)) = obstacle_blocks(obstacle_y, gap_center_y, gap_height);
+
+    if top_height > 0. && aabb_overlap(rock_cx, rock_y, ROCK_HEIGHT, ROCK_HEIGHT, obstacle_x, top_cy, OBSTACLE_WIDTH, top_height) {
+        return true;
+    }
+
+    if bottom_height > 0. && aabb_overlap(rock_cx, rock_y, ROCK_HEIGHT, ROCK_HEIGHT, obstacle_x, bottom_cy, OBSTACLE_WIDTH, bottom_height) {
+        return true;
+    }
+
+    false
+}
+
+/// 柱Sprite用のTransformを作る。元のSpriteはOBSTACLE_HEIGHT基準の1コマなので、
+/// 実際のブロック高さに合わせてY方向だけスケールして表示する
+fn pillar_transform(x: f32, block_cy: f32, block_height: f32) -> Transform {
+    let mut transform = Transform::default();
+    transform.set_translation_xyz(x, block_cy, 0.);
+    transform.set_scale(Vector3::new(1., block_height / OBSTACLE_HEIGHT, 1.));
+    transform
+}
+
+/// 柱Sprite用のSpriteRenderを作る(上下どちらのブロックも同じ1コマを使い回す)
+fn pillar_sprite(sprite_sheet_handle: &Handle<SpriteSheet>) -> SpriteRender {
+    SpriteRender {
+        sprite_sheet: sprite_sheet_handle.clone(),
+        sprite_number: OBSTACLE_SPRITE_INDEX,
+    }
+}
+
+/// 岩とObstacleが衝突した、あるいは地面に着いたときに立てるフラグ
+/// (SystemからはStateの遷移を直接行えないため、Stateが毎フレーム監視する)
+#[derive(Default)]
+pub struct GameOverTrigger(pub bool);
 
 
 //ゲームを実行するシステム
-pub struct PlaySystem;
+//jump_was_downで前フレームのJumpアクションの状態を保持し、押した瞬間(edge)だけを検知する
+#[derive(Default)]
+pub struct PlaySystem {
+    jump_was_down: bool,
+}
 
 //システムを作るときはSystemのTraitを用いる
 impl<'a> System<'a> for PlaySystem{
@@ -35,21 +118,30 @@ impl<'a> System<'a> for PlaySystem{
         WriteStorage<'a, Transform>, // TransformはEntityの座標やサイズをそうさするComponentへの書き込み
         WriteStorage<'a, Rock>,      // 岩の情報を持ったComponentへの書き込み
         WriteStorage<'a, Obstacle>,  // 障害物の情報を持ったComponentへの書き込み
+        ReadStorage<'a, Pillars>,    // Obstacleに追従させる柱SpriteのEntity参照
+        WriteStorage<'a, SpriteRender>, // 岩のパタパタアニメーション用コマ送り
         Read<'a, InputHandler<StringBindings>>, // ユーザーからの入力に関するComponentを読み込み
         Read<'a, Time>,              // 時間Componentの読み込み
+        ReadExpect<'a, Sounds>,
+        Read<'a, AssetStorage<Source>>,
+        Option<Read<'a, Output>>,
     );
-    
+
     //システムの実行関数
-    fn run(&mut self, (mut transforms, mut rocks, mut obstacles, input, time): Self::SystemData) {
+    fn run(&mut self, (mut transforms, mut rocks, mut obstacles, pillars, mut sprites, input, time, sounds, storage, output): Self::SystemData) {
+        // "Jump"アクションが押された瞬間かどうかを判定(キーボード/ScanCode/ゲームパッドはbindings.ronで設定)
+        let jump_down = input.action_is_down("Jump").unwrap_or(false);
+        let jump_pressed = jump_down && !self.jump_was_down;
+        self.jump_was_down = jump_down;
+
         // joinによってEntityを共有しているComponentの集合を得ることが可能
-        for (transform,rock) in (&mut transforms, &mut rocks).join()
+        for (transform,rock, sprite_render) in (&mut transforms, &mut rocks, &mut sprites).join()
         {
             //前フレームからの経過時間を取得
             let dt = time.delta_real_seconds() * 70.;
-            // Enterキーの入力を検知
-            if input.key_is_down(VirtualKeyCode::Return){
-                //
+            if jump_pressed {
                 rock.set_velocity(7.);
+                play_sound(&sounds.flap_sfx, &storage, output.as_deref());
             }
 
              // 基本的には下向きへの加速
@@ -68,24 +160,345 @@ impl<'a> System<'a> for PlaySystem{
              rock.set_y(new_y);
              rock.set_velocity(new_velocity);
 
+             // 速度に応じてZ軸回転(上昇中は頭が上、下降中は頭が下になるように傾ける)
+             let tilt = (new_velocity * ROCK_TILT_FACTOR).max(-ROCK_TILT_MAX).min(ROCK_TILT_MAX);
+             transform.set_rotation_2d(tilt);
+
+             // パタパタアニメーション: 一定時間ごとにコマを進める
+             rock.frame_timer += time.delta_real_seconds();
+             if rock.frame_timer >= ROCK_FLAP_FRAME_DURATION {
+                 rock.frame_timer = 0.;
+                 rock.frame_index = (rock.frame_index + 1) % ROCK_FLAP_FRAME_COUNT;
+             }
+             sprite_render.sprite_number = rock.frame_index;
         }
 
-        // 同様に障害物のComponentを取得
-        for (transform, obstacle) in (&mut transforms, &mut obstacles).join() {
-            // 左に進みます
+        // 同様に障害物のComponentを取得。柱Sprite側のTransformはこのEntityを借用したままでは
+        // 書き換えられないので、新しいXだけ集めておいて後段でまとめて反映する
+        let mut pillar_xs: Vec<(Entity, f32)> = Vec::new();
+        for (transform, obstacle, obstacle_pillars) in (&mut transforms, &mut obstacles, &pillars).join() {
+            // 左に進みます。画面外に出た後の回収・再利用はSpawnSystemが担当する
             let dt = time.delta_real_seconds() * 70.;
-            let mut new_x = obstacle.x - 5. * dt;
+            let new_x = obstacle.x - 5. * dt;
 
-            // 左端についたら右端へ移動させます
-            if new_x <= -OBSTACLE_WIDTH / 2. {
-                new_x = SCREEN_WIDTH;
-            }
             obstacle.set_x(new_x);
             transform.set_translation_x(new_x);
+
+            pillar_xs.push((obstacle_pillars.top, new_x));
+            pillar_xs.push((obstacle_pillars.bottom, new_x));
+        }
+        for (pillar_entity, new_x) in pillar_xs {
+            if let Some(pillar_transform) = transforms.get_mut(pillar_entity) {
+                pillar_transform.set_translation_x(new_x);
+            }
+        }
+    }
+}
+
+
+//岩とObstacleの衝突、および地面への着地を判定するシステム
+pub struct CollisionSystem;
+
+impl<'a> System<'a> for CollisionSystem {
+    type SystemData = (
+        ReadStorage<'a, Rock>,
+        ReadStorage<'a, Obstacle>,
+        Write<'a, GameOverTrigger>,
+        ReadExpect<'a, Sounds>,
+        Read<'a, AssetStorage<Source>>,
+        Option<Read<'a, Output>>,
+    );
+
+    fn run(&mut self, (rocks, obstacles, mut game_over, sounds, storage, output): Self::SystemData) {
+        let was_already_over = game_over.0;
+
+        for rock in (&rocks).join() {
+            // 地面についたらゲームオーバー
+            if rock.y <= ROCK_HEIGHT / 2. {
+                game_over.0 = true;
+            }
+
+            for obstacle in (&obstacles).join() {
+                if rock_hits_obstacle(rock.y, obstacle.x, obstacle.y, obstacle.gap_center_y, obstacle.gap_height) {
+                    game_over.0 = true;
+                }
+            }
         }
+
+        if game_over.0 && !was_already_over {
+            play_sound(&sounds.hit_sfx, &storage, output.as_deref());
+        }
+    }
+}
+
+/// 次にObstacleを生成するまでの経過時間を保持するリソース
+pub struct SpawnTimer {
+    elapsed: f32,
+}
+
+impl Default for SpawnTimer {
+    fn default() -> SpawnTimer {
+        SpawnTimer { elapsed: 0. }
     }
 }
 
+/// 画面外に出て役目を終えたObstacle Entityを保持しておく待機列。
+/// 削除してbuild_entityで新規に確保し直すのではなく、ここにあるEntityへComponentを
+/// 詰め直して使い回すことで、ランダム生成されるObstacleのEntity数をOBSTACLE_POOL_SIZE以下に抑える
+/// (柱Entityは1つのObstacleにつき2つずつ別に確保される)。
+/// PlayStateが終了すると中身のEntityごと削除されるため、PlayState::on_startで必ずリセットする
+#[derive(Default)]
+pub struct ObstaclePool {
+    free: Vec<Entity>,
+}
+
+//画面右端からObstacleを周期的に生成し、画面外に出たものをプールに回収して再利用するシステム
+//固定のプールサイズ(OBSTACLE_POOL_SIZE)を超えて生成しないことで、
+//Entityの生成・削除が無制限に積み重ならないようにしている
+pub struct SpawnSystem;
+
+impl<'a> System<'a> for SpawnSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Obstacle>,
+        WriteStorage<'a, SpriteRender>,
+        WriteStorage<'a, PlayStateEntity>,
+        WriteStorage<'a, Pillars>,
+        Option<Read<'a, Handle<SpriteSheet>>>,
+        Write<'a, SpawnTimer>,
+        Write<'a, ObstaclePool>,
+        Read<'a, Time>,
+        Option<Read<'a, Level>>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut transforms, mut obstacles, mut sprites, mut play_state_entities, mut pillars, sprite_sheet_handle, mut timer, mut pool, time, level): Self::SystemData,
+    ) {
+        // PlayState::on_startがSpriteSheetHandleを挿入するまでは、ReadyState等で
+        // dispatcherが回ってもこのSystemには何もするものがないので抜ける
+        let sprite_sheet_handle = match sprite_sheet_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        // 左端を通り過ぎたObstacleはEntityを削除せず、Obstacle/Transformと柱のSpriteだけ外してプールに戻す。
+        // Pillars自体は外さない(同じ柱Entityを次の再利用時にも使い回すため)
+        let passed: Vec<Entity> = (&entities, &obstacles)
+            .join()
+            .filter(|(_, obstacle)| obstacle.x <= -OBSTACLE_WIDTH / 2.)
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in passed {
+            if let Some(&entity_pillars) = pillars.get(entity) {
+                sprites.remove(entity_pillars.top);
+                sprites.remove(entity_pillars.bottom);
+            }
+            transforms.remove(entity);
+            obstacles.remove(entity);
+            pool.free.push(entity);
+        }
+
+        // PNGから読み込んだLevelがある場合は、そのコースをそのまま使い乱数生成はしない
+        if level.is_some() {
+            return;
+        }
+
+        timer.elapsed += time.delta_real_seconds();
+        if timer.elapsed < OBSTACLE_SPAWN_INTERVAL {
+            return;
+        }
+        timer.elapsed = 0.;
+
+        // プールに空きが無く、かつ既にプールサイズいっぱいまでEntityを確保済みなら生成しない
+        let allocated = (&obstacles).join().count() + pool.free.len();
+        if pool.free.is_empty() && allocated >= OBSTACLE_POOL_SIZE {
+            return;
+        }
+
+        let gap_center_y = rand::thread_rng().gen_range(OBSTACLE_GAP_HEIGHT..(SCREEN_HEIGHT - OBSTACLE_GAP_HEIGHT));
+        let ((top_cy, top_height), (bottom_cy, bottom_height)) = obstacle_blocks(gap_center_y, gap_center_y, OBSTACLE_GAP_HEIGHT);
+
+        let mut obstacle_transform = Transform::default();
+        obstacle_transform.set_translation_xyz(SCREEN_WIDTH, gap_center_y, 0.);
+        let obstacle = Obstacle::new_with_gap(SCREEN_WIDTH, gap_center_y, OBSTACLE_GAP_HEIGHT);
+
+        if let Some(entity) = pool.free.pop() {
+            // 既存のEntityにComponentを詰め直して再利用する。柱EntityはPillarsに残っているものを使う
+            let entity_pillars = *pillars.get(entity).expect("プールされたObstacleには柱Entityが設定されているはず");
+            transforms.insert(entity, obstacle_transform).expect("Obstacleの再利用に失敗しました");
+            obstacles.insert(entity, obstacle).expect("Obstacleの再利用に失敗しました");
+
+            transforms.insert(entity_pillars.top, pillar_transform(SCREEN_WIDTH, top_cy, top_height)).expect("柱の再利用に失敗しました");
+            sprites.insert(entity_pillars.top, pillar_sprite(&sprite_sheet_handle)).expect("柱の再利用に失敗しました");
+            transforms.insert(entity_pillars.bottom, pillar_transform(SCREEN_WIDTH, bottom_cy, bottom_height)).expect("柱の再利用に失敗しました");
+            sprites.insert(entity_pillars.bottom, pillar_sprite(&sprite_sheet_handle)).expect("柱の再利用に失敗しました");
+        } else {
+            let top = entities.create();
+            transforms.insert(top, pillar_transform(SCREEN_WIDTH, top_cy, top_height)).expect("柱の生成に失敗しました");
+            sprites.insert(top, pillar_sprite(&sprite_sheet_handle)).expect("柱の生成に失敗しました");
+            play_state_entities.insert(top, PlayStateEntity).expect("柱の生成に失敗しました");
+
+            let bottom = entities.create();
+            transforms.insert(bottom, pillar_transform(SCREEN_WIDTH, bottom_cy, bottom_height)).expect("柱の生成に失敗しました");
+            sprites.insert(bottom, pillar_sprite(&sprite_sheet_handle)).expect("柱の生成に失敗しました");
+            play_state_entities.insert(bottom, PlayStateEntity).expect("柱の生成に失敗しました");
+
+            entities
+                .build_entity()
+                .with(obstacle_transform, &mut transforms)
+                .with(obstacle, &mut obstacles)
+                .with(Pillars { top, bottom }, &mut pillars)
+                .with(PlayStateEntity, &mut play_state_entities)
+                .build();
+        }
+    }
+}
+
+/// 現在のスコア
+#[derive(Default)]
+pub struct Score(pub i32);
+
+//岩の固定X座標をObstacleが通過した瞬間を検知してスコアを加算するシステム
+pub struct ScoreSystem;
+
+impl<'a> System<'a> for ScoreSystem {
+    type SystemData = (
+        WriteStorage<'a, Obstacle>,
+        WriteStorage<'a, UiText>,
+        ReadStorage<'a, ScoreDisplay>,
+        Write<'a, Score>,
+        ReadExpect<'a, Sounds>,
+        Read<'a, AssetStorage<Source>>,
+        Option<Read<'a, Output>>,
+    );
+
+    fn run(&mut self, (mut obstacles, mut ui_texts, score_displays, mut score, sounds, storage, output): Self::SystemData) {
+        let rock_cx = SCREEN_WIDTH / 4.;
+
+        for obstacle in (&mut obstacles).join() {
+            // 前フレームと今フレームの間でObstacleが岩のX座標をまたいだら得点
+            if obstacle.prev_x > rock_cx && obstacle.x <= rock_cx {
+                score.0 += 1;
+                play_sound(&sounds.point_sfx, &storage, output.as_deref());
+            }
+            obstacle.prev_x = obstacle.x;
+        }
+
+        for (ui_text, _) in (&mut ui_texts, &score_displays).join() {
+            ui_text.text = score.0.to_string();
+        }
+    }
+}
+
+/// PlayState中に生成されたEntityの目印。
+/// on_stopで`delete_all`してしまうとPausedStateのオーバーレイ等も巻き込んで消えるため、
+/// このマーカーを持つEntityだけを個別に削除する(state-scopedな削除)
+#[derive(Default)]
+pub struct PlayStateEntity;
+
+impl Component for PlayStateEntity {
+    type Storage = NullStorage<Self>;
+}
+
+/// ReadyState中に生成されたEntityの目印
+#[derive(Default)]
+pub struct ReadyStateEntity;
+
+impl Component for ReadyStateEntity {
+    type Storage = NullStorage<Self>;
+}
+
+/// GameOverState中に生成されたEntityの目印
+#[derive(Default)]
+pub struct GameOverStateEntity;
+
+impl Component for GameOverStateEntity {
+    type Storage = NullStorage<Self>;
+}
+
+/// スコア表示用のUiTextであることを示す目印
+#[derive(Default)]
+pub struct ScoreDisplay;
+
+impl Component for ScoreDisplay {
+    type Storage = NullStorage<Self>;
+}
+
+/// マーカーComponent`T`を持つEntityだけをまとめて削除する
+fn delete_entities_with<T: Component>(world: &mut World) {
+    let entities: Vec<Entity> = (&world.entities(), &world.read_storage::<T>())
+        .join()
+        .map(|(entity, _)| entity)
+        .collect();
+    world
+        .delete_entities(&entities)
+        .expect("Entityの削除に失敗しました");
+}
+
+/// ゲーム中に再生する効果音をまとめて保持するリソース
+pub struct Sounds {
+    pub flap_sfx: Handle<Source>,
+    pub point_sfx: Handle<Source>,
+    pub hit_sfx: Handle<Source>,
+}
+
+fn load_audio_track(loader: &Loader, world: &World, file: &str) -> Handle<Source> {
+    loader.load(file, AudioFormat::Ogg, (), &world.read_resource())
+}
+
+/// 効果音をロードしてWorldにSoundsリソースとして追加する
+pub fn initialise_audio(world: &mut World) {
+    let sounds = {
+        let loader = world.read_resource::<Loader>();
+        Sounds {
+            flap_sfx: load_audio_track(&loader, world, "audio/flap.ogg"),
+            point_sfx: load_audio_track(&loader, world, "audio/point.ogg"),
+            hit_sfx: load_audio_track(&loader, world, "audio/hit.ogg"),
+        }
+    };
+    world.insert(sounds);
+}
+
+/// Sourceを一度だけ再生する。出力デバイスが無い環境では何もしない
+fn play_sound(sound: &Handle<Source>, storage: &AssetStorage<Source>, output: Option<&Output>) {
+    if let Some(output) = output {
+        if let Some(sound) = storage.get(sound) {
+            output.play_once(sound, 1.0);
+        }
+    }
+}
+
+/// タイトル画面のState。ゲーム起動直後に表示され、ジャンプキーでPlayStateに遷移する
+struct ReadyState;
+
+impl SimpleState for ReadyState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        // 効果音は最初の1回だけロードすればよい
+        if data.world.try_fetch::<Sounds>().is_none() {
+            initialise_audio(data.world);
+        }
+        create_ui_text(data.world, "ready_text", 0., "Enterキーでスタート", 40., ReadyStateEntity);
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        delete_entities_with::<ReadyStateEntity>(data.world);
+    }
+
+    fn handle_event(&mut self, _data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans {
+        // "Jump"アクション(bindings.ronでキーボード/ScanCode/ゲームパッドを割り当て)が押された
+        // 瞬間を使う。InputEvent::ActionPressedはそもそも押された瞬間にしか発火しないイベントなので
+        // PlaySystemのようにフラグを自前で持って前フレームと比較する必要はない
+        if let StateEvent::Input(InputEvent::ActionPressed(action)) = &event {
+            if action == "Jump" {
+                return Trans::Push(Box::new(PlayState));
+            }
+        }
+
+        Trans::None
+    }
+}
 
 /// ゲーム画面のState
 struct PlayState;
@@ -96,12 +509,117 @@ impl SimpleState for PlayState {
         let sprite_sheet_handle = load_sprite_sheet(data.world);
         set_camera(data.world);
         set_rock(data.world,sprite_sheet_handle.clone());
-        set_obstacle(data.world,sprite_sheet_handle);
+
+        // レベルPNGがあればそのコースを、無ければSpawnSystemによるランダム生成を使う
+        if let Some(level) = load_level() {
+            spawn_level_obstacles(data.world, &level, sprite_sheet_handle.clone());
+            data.world.insert(level);
+        }
+
+        // SpawnSystemがこのリソースを使ってObstacleを生成する
+        data.world.insert(sprite_sheet_handle);
+        data.world.insert(Score::default());
+        // 前回のプレイから持ち越されている可能性があるフラグを念のためリセットする
+        data.world.insert(GameOverTrigger::default());
+        // 前回のプレイのObstaclePoolをそのまま引き継ぐと、on_stopで削除済みのEntityが
+        // free一覧に残ったままになりSpawnSystemがそれを再利用しようとして失敗するため、空で作り直す
+        data.world.insert(ObstaclePool::default());
+        create_score_text(data.world);
     }
 
-    // PlayStateがPopされるときに実行される
+    // PlayStateがPopされるときに実行される。PlayStateが生成したEntityだけを削除する
     fn on_stop(&mut self , data: StateData<'_, GameData<'_, '_>>){
-        data.world.delete_all();
+        delete_entities_with::<PlayStateEntity>(data.world);
+        // SpawnSystemはこのHandleの有無でPlayState中かどうかを判断するので、戻った先のReadyStateで
+        // Obstacleが生成されないよう取り除いておく
+        data.world.remove::<Handle<SpriteSheet>>();
+    }
+
+    fn handle_event(&mut self, _data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if is_key_down(event, VirtualKeyCode::P) {
+                return Trans::Push(Box::new(PausedState));
+            }
+        }
+
+        Trans::None
+    }
+
+    fn update(&mut self, data: StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        data.data.update(&data.world);
+
+        let mut game_over = data.world.write_resource::<GameOverTrigger>();
+        if game_over.0 {
+            game_over.0 = false;
+            let final_score = data.world.read_resource::<Score>().0;
+            return Trans::Push(Box::new(GameOverState::new(final_score)));
+        }
+
+        Trans::None
+    }
+}
+
+/// 一時停止画面のState。updateでdispatcherを回さないのでシミュレーションが止まる
+struct PausedState;
+
+impl SimpleState for PausedState {
+    fn handle_event(&mut self, _data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if is_key_down(event, VirtualKeyCode::P) {
+                return Trans::Pop;
+            }
+        }
+
+        Trans::None
+    }
+
+    // SimpleStateのデフォルト実装はdata.data.update(&data.world)を呼んでしまい、
+    // ドキュメント通りにシミュレーションを止めるにはこれを上書きして何もしない必要がある
+    fn update(&mut self, _data: StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        Trans::None
+    }
+}
+
+/// ゲームオーバー画面のState。最終スコアを表示し、Enterキーでタイトルへ戻る
+struct GameOverState {
+    final_score: i32,
+}
+
+impl GameOverState {
+    fn new(final_score: i32) -> GameOverState {
+        GameOverState { final_score }
+    }
+}
+
+impl SimpleState for GameOverState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        let text = format!("ゲームオーバー スコア: {}\nEnterキーでタイトルへ", self.final_score);
+        create_ui_text(data.world, "game_over_text", 0., &text, 40., GameOverStateEntity);
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        delete_entities_with::<GameOverStateEntity>(data.world);
+    }
+
+    fn handle_event(&mut self, _data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans {
+        // "Jump"アクションの押下で再戦する。ReadyStateと同じくbindings.ronの割り当てに従う
+        if let StateEvent::Input(InputEvent::ActionPressed(action)) = &event {
+            if action == "Jump" {
+                // Trans::Switchはスタック最上段(このGameOverState)を置き換えるだけで、
+                // 下に残ったPlayStateはon_stopされず生き続けてしまう(再戦のたびにEntityが増殖するバグの原因だった)。
+                // Ready->PlayState->GameOverStateの2段を両方Popして、元からスタックに残っている
+                // ReadyStateまで戻すことで、PlayStateのon_stopを確実に呼びEntityを片付ける。
+                return Trans::Sequence(vec![Trans::Pop, Trans::Pop]);
+            }
+        }
+
+        Trans::None
+    }
+
+    // ここでdispatcherを回してしまうと、まだ削除されていない死んだRock/Obstacleに対して
+    // CollisionSystemが反応し続け、GameOverTriggerを毎フレーム再び立ててしまう
+    fn update(&mut self, _data: StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        Trans::None
     }
 }
 
@@ -112,7 +630,8 @@ pub fn set_camera(world: &mut World){
         .create_entity()
         .with(camera_transform)
         .with(Camera::standard_2d(SCREEN_WIDTH, SCREEN_HEIGHT))
-        .build(); 
+        .with(PlayStateEntity)
+        .build();
 }
 
 /// 岩EntityをWorldに追加します
@@ -121,7 +640,7 @@ pub fn set_rock(world: &mut World, sprite_sheet_handle: Handle<SpriteSheet>) {
     rock_transform.set_translation_xyz(SCREEN_WIDTH / 4., 0., 0.);
     let rock_sprite_render = SpriteRender {
         sprite_sheet: sprite_sheet_handle,
-        sprite_number: 0, //SpriteSheet中の画像の1つ目
+        sprite_number: 0, //パタパタアニメーションの1コマ目
     };
 
     world
@@ -129,23 +648,7 @@ pub fn set_rock(world: &mut World, sprite_sheet_handle: Handle<SpriteSheet>) {
         .with(rock_transform)
         .with(Rock::new())
         .with(rock_sprite_render)
-        .build();
-}
-
-/// 障害物EntityをWorldに追加します
-pub fn set_obstacle(world: &mut World, sprite_sheet_handle : Handle<SpriteSheet>){
-
-    let mut obstacle_transform = Transform::default();
-    obstacle_transform.set_translation_xyz(SCREEN_HEIGHT -10. , OBSTACLE_HEIGHT /2. - 30. ,0.);
-    let obstacle_sprite_render = SpriteRender{
-        sprite_sheet: sprite_sheet_handle,
-        sprite_number: 1,
-    };
-
-    world.create_entity()
-        .with(obstacle_transform)
-        .with(Obstacle::new())
-        .with(obstacle_sprite_render)
+        .with(PlayStateEntity)
         .build();
 }
 
@@ -171,12 +674,140 @@ pub fn load_sprite_sheet(world: &World) -> Handle<SpriteSheet>{
     )
 }
 
+/// PNGレベルマップから読み取った、各Obstacleの隙間の中心Y座標
+pub struct Level {
+    gap_centers: Vec<f32>,
+}
+
+/// assets/texture/level.pngを読み込み、LEVEL_COLUMN_STRIDE pxおきの列について、その列の縦中央のピクセルの
+/// 明るさを隙間の中心Y座標にマッピングする。
+/// レベルPNGが存在しない場合はNoneを返し、呼び出し側はランダム生成にフォールバックできる
+pub fn load_level() -> Option<Level> {
+    let app_root = application_root_dir().ok()?;
+    let level_path = app_root.join("assets").join("texture").join("level.png");
+    let image = image::open(&level_path).ok()?;
+
+    let width = image.width();
+    let height = image.height();
+    let mut gap_centers = Vec::new();
+
+    let mut x = 0;
+    while x < width {
+        let pixel = image.get_pixel(x, height / 2);
+        let brightness = (pixel.0[0] as u32 + pixel.0[1] as u32 + pixel.0[2] as u32) / 3;
+        let ratio = brightness as f32 / 255.;
+        let gap_center_y = OBSTACLE_GAP_HEIGHT + ratio * (SCREEN_HEIGHT - OBSTACLE_GAP_HEIGHT * 2.);
+        gap_centers.push(gap_center_y);
+        x += LEVEL_COLUMN_STRIDE;
+    }
+
+    Some(Level { gap_centers })
+}
+
+/// Levelの各隙間にあわせて、一定間隔でObstacleをWorldに追加します
+pub fn spawn_level_obstacles(world: &mut World, level: &Level, sprite_sheet_handle: Handle<SpriteSheet>) {
+    for (i, &gap_center_y) in level.gap_centers.iter().enumerate() {
+        let x = SCREEN_WIDTH + i as f32 * OBSTACLE_SPACING;
+        let ((top_cy, top_height), (bottom_cy, bottom_height)) = obstacle_blocks(gap_center_y, gap_center_y, OBSTACLE_GAP_HEIGHT);
+
+        let top = world
+            .create_entity()
+            .with(pillar_transform(x, top_cy, top_height))
+            .with(pillar_sprite(&sprite_sheet_handle))
+            .with(PlayStateEntity)
+            .build();
+        let bottom = world
+            .create_entity()
+            .with(pillar_transform(x, bottom_cy, bottom_height))
+            .with(pillar_sprite(&sprite_sheet_handle))
+            .with(PlayStateEntity)
+            .build();
+
+        let mut obstacle_transform = Transform::default();
+        obstacle_transform.set_translation_xyz(x, gap_center_y, 0.);
+
+        world
+            .create_entity()
+            .with(obstacle_transform)
+            .with(Obstacle::new_with_gap(x, gap_center_y, OBSTACLE_GAP_HEIGHT))
+            .with(Pillars { top, bottom })
+            .with(PlayStateEntity)
+            .build();
+    }
+}
+
+/// UIテキスト表示に使うフォントのHandleをキャッシュしておくリソース
+pub struct FontCache(Handle<FontAsset>);
+
+// UIテキスト表示に使うフォントをロードする。一度ロードしたHandleはFontCacheとしてWorldに
+// キャッシュし、以後の呼び出しではディスクから読み直さずそれを使い回す
+pub fn load_font(world: &mut World) -> Handle<FontAsset> {
+    if let Some(cache) = world.try_fetch::<FontCache>() {
+        return cache.0.clone();
+    }
+
+    let font = {
+        let loader = world.read_resource::<Loader>();
+        let font_storage = world.read_resource::<AssetStorage<FontAsset>>();
+        loader.load("font/square.ttf", TtfFormat, (), &font_storage)
+    };
+    world.insert(FontCache(font.clone()));
+    font
+}
+
+/// マーカーComponent`marker`を付けた、静的なメッセージ表示用UiTextEntityを生成する
+pub fn create_ui_text<T: Component>(world: &mut World, id: &str, y: f32, text: &str, font_size: f32, marker: T) -> Entity {
+    let font = load_font(world);
+    let ui_transform = UiTransform::new(
+        id.to_string(),
+        Anchor::Middle,
+        Anchor::Middle,
+        0.,
+        y,
+        1.,
+        400.,
+        50.,
+    );
+
+    world
+        .create_entity()
+        .with(ui_transform)
+        .with(UiText::new(font, text.to_string(), [1., 1., 1., 1.], font_size))
+        .with(marker)
+        .build()
+}
+
+/// スコア表示用のUiTextEntityをWorldに追加します
+pub fn create_score_text(world: &mut World) {
+    let font = load_font(world);
+    let ui_transform = UiTransform::new(
+        "score_text".to_string(),
+        Anchor::TopMiddle,
+        Anchor::TopMiddle,
+        0.,
+        -30.,
+        1.,
+        200.,
+        50.,
+    );
+
+    world
+        .create_entity()
+        .with(ui_transform)
+        .with(UiText::new(font, "0".to_string(), [1., 1., 1., 1.], 40.))
+        .with(ScoreDisplay)
+        .with(PlayStateEntity)
+        .build();
+}
+
 
 
 /// 岩の情報を保持するComponent
 pub struct Rock{
     y: f32,
     velocity: f32,
+    frame_timer: f32, // 次のコマ送りまでの経過時間
+    frame_index: usize, // 現在表示しているパタパタアニメーションのコマ
 }
 
 impl Rock{
@@ -184,6 +815,8 @@ impl Rock{
         Rock{
             y: 100. ,
             velocity: 0. ,
+            frame_timer: 0.,
+            frame_index: 0,
         }
     }
 
@@ -206,12 +839,21 @@ impl Component for Rock {
 /// 障害物の情報を保持するComponent
 pub struct Obstacle {
     x: f32, // 障害物は右から左にいくだけなのでx座標のみ
+    prev_x: f32, // ScoreSystemが通過判定に使う、前フレームのx
+    y: f32, // 当たり判定用。Transformと同じ値を保持する
+    gap_center_y: f32, // 上下のブロックの間にある隙間の中心Y座標
+    gap_height: f32,   // 隙間の高さ。0なら隙間なし(単一の障害物)として扱う
 }
 
 impl Obstacle {
-    pub fn new() -> Obstacle{
-        Obstacle{
-            x: SCREEN_HEIGHT -10. ,
+    /// 指定したX座標・隙間でObstacleを生成する(SpawnSystemが使用)
+    pub fn new_with_gap(x: f32, gap_center_y: f32, gap_height: f32) -> Obstacle {
+        Obstacle {
+            x,
+            prev_x: x,
+            y: gap_center_y,
+            gap_center_y,
+            gap_height,
         }
     }
 
@@ -224,6 +866,18 @@ impl Component for Obstacle {
     type Storage = DenseVecStorage<Self>;
 }
 
+/// Obstacleの見た目を構成する上下2枚の柱SpriteのEntityを指す。
+/// Obstacle自体には見た目が無く、隙間の上下にある柱Entityの位置をPlaySystemが毎フレーム追従させる
+#[derive(Clone, Copy)]
+pub struct Pillars {
+    top: Entity,
+    bottom: Entity,
+}
+
+impl Component for Pillars {
+    type Storage = DenseVecStorage<Self>;
+}
+
 
 fn main() -> amethyst::Result<()> {
     amethyst::start_logger(Default::default());
@@ -234,9 +888,10 @@ fn main() -> amethyst::Result<()> {
     let assets_dir = app_root.join("assets");
     let config_dir = app_root.join("config");
     let display_config_path = config_dir.join("display.ron");
+    let bindings_config_path = config_dir.join("bindings.ron");
 
-    //入力システム
-    let input_bundle = InputBundle::<StringBindings>::new();
+    //入力システム。キー割り当てはbindings.ronで管理し、再コンパイルなしで変更できるようにする
+    let input_bundle = InputBundle::<StringBindings>::new().with_bindings_from_file(bindings_config_path)?;
 
     //ゲームデータ作成　システム　設定を追加
     let game_data = GameDataBuilder::default()
@@ -250,12 +905,89 @@ fn main() -> amethyst::Result<()> {
         )?
         .with_bundle(TransformBundle::new())?
         .with_bundle(input_bundle)?
-        .with(PlaySystem, "play_system", &[]);
+        .with_bundle(UiBundle::<StringBindings>::new())?
+        .with_bundle(AudioBundle::default())?
+        .with(PlaySystem::default(), "play_system", &[])
+        .with(SpawnSystem, "spawn_system", &["play_system"])
+        .with(ScoreSystem, "score_system", &["play_system"])
+        .with(CollisionSystem, "collision_system", &["play_system", "spawn_system"]);
 
     // アセットのパスと初期Stateとゲームデータによってゲームを作成
-    let mut game = Application::new(assets_dir, PlayState, game_data)?;
+    let mut game = Application::new(assets_dir, ReadyState, game_data)?;
 
     game.run();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aabb_overlap_detects_overlapping_rects() {
+        assert!(aabb_overlap(0., 0., 10., 10., 5., 5., 10., 10.));
+    }
+
+    #[test]
+    fn aabb_overlap_ignores_separated_rects() {
+        assert!(!aabb_overlap(0., 0., 10., 10., 100., 100., 10., 10.));
+    }
+
+    #[test]
+    fn aabb_overlap_touching_edges_do_not_count() {
+        // 中心間距離がちょうど半幅の和に等しい場合は「触れているだけ」で重なりとはしない(不等号は厳密未満)
+        assert!(!aabb_overlap(0., 0., 10., 10., 10., 0., 10., 10.));
+    }
+
+    #[test]
+    fn obstacle_blocks_splits_around_the_gap() {
+        let ((top_cy, top_height), (bottom_cy, bottom_height)) = obstacle_blocks(0., 0., OBSTACLE_GAP_HEIGHT);
+
+        let gap_top = OBSTACLE_GAP_HEIGHT / 2.;
+        let gap_bottom = -OBSTACLE_GAP_HEIGHT / 2.;
+        let expected_top_height = (OBSTACLE_HEIGHT / 2. - gap_top).max(0.);
+        let expected_bottom_height = (gap_bottom - (-OBSTACLE_HEIGHT / 2.)).max(0.);
+
+        assert_eq!(top_height, expected_top_height);
+        assert_eq!(bottom_height, expected_bottom_height);
+        assert_eq!(top_cy, gap_top + expected_top_height / 2.);
+        assert_eq!(bottom_cy, gap_bottom - expected_bottom_height / 2.);
+    }
+
+    #[test]
+    fn obstacle_blocks_has_no_block_when_gap_reaches_the_edge() {
+        // 隙間の端が障害物の上端・下端までちょうど届く場合、その側のブロックは高さ0になる
+        let ((_, top_height), (_, bottom_height)) = obstacle_blocks(0., 0., OBSTACLE_HEIGHT);
+
+        assert_eq!(top_height, 0.);
+        assert_eq!(bottom_height, 0.);
+    }
+
+    #[test]
+    fn rock_passes_through_the_gap() {
+        assert!(!rock_hits_obstacle(0., 0., 0., 0., OBSTACLE_GAP_HEIGHT));
+    }
+
+    #[test]
+    fn rock_hits_the_top_block() {
+        let gap_center_y = 0.;
+        let top_block_cy = gap_center_y + OBSTACLE_GAP_HEIGHT / 2. + (OBSTACLE_HEIGHT - OBSTACLE_GAP_HEIGHT) / 4.;
+        assert!(rock_hits_obstacle(top_block_cy, 0., 0., gap_center_y, OBSTACLE_GAP_HEIGHT));
+    }
+
+    #[test]
+    fn rock_hits_the_bottom_block() {
+        let gap_center_y = 0.;
+        let bottom_block_cy = gap_center_y - OBSTACLE_GAP_HEIGHT / 2. - (OBSTACLE_HEIGHT - OBSTACLE_GAP_HEIGHT) / 4.;
+        assert!(rock_hits_obstacle(bottom_block_cy, 0., 0., gap_center_y, OBSTACLE_GAP_HEIGHT));
+    }
+
+    #[test]
+    fn rock_misses_when_outside_the_obstacle_x_range() {
+        // Y座標的には上側ブロックと重なる高さでも、ObstacleのXから十分離れていれば衝突しない
+        let gap_center_y = 0.;
+        let top_block_cy = gap_center_y + OBSTACLE_GAP_HEIGHT / 2. + (OBSTACLE_HEIGHT - OBSTACLE_GAP_HEIGHT) / 4.;
+        assert!(!rock_hits_obstacle(top_block_cy, 1000., 0., gap_center_y, OBSTACLE_GAP_HEIGHT));
+    }
+}